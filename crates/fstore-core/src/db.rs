@@ -0,0 +1,174 @@
+use crate::{
+    error::Result,
+    store::{DatabaseConfig, Job, JobKind},
+};
+
+use chrono::{DateTime, Local};
+use futures::stream::{BoxStream, StreamExt};
+use fstore::ObjectError;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::{result, time::Duration};
+use uuid::Uuid;
+
+/// A row of the `objects` table, as seen by the background scan jobs
+/// (`archive`/`check`/`repair`/`migrate_store`).
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct Object {
+    pub object_id: Uuid,
+    pub hash: String,
+    pub size: i64,
+}
+
+#[derive(Clone)]
+pub struct Database {
+    pool: PgPool,
+}
+
+impl Database {
+    pub async fn from_config(
+        config: &DatabaseConfig,
+    ) -> result::Result<Self, String> {
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect_with(config.connection.clone().into())
+            .await
+            .map_err(|err| format!("failed to connect to database: {err}"))?;
+
+        Ok(Self { pool })
+    }
+
+    /// Claims the next `job_queue` row of `kind`: resumes the existing row
+    /// if one is already `new` or `running` (a worker crashed before
+    /// completing it), otherwise inserts a fresh one and marks it
+    /// `running`.
+    pub async fn claim_job(&self, kind: JobKind) -> Result<Job> {
+        let job = sqlx::query_as!(
+            Job,
+            r#"
+            INSERT INTO job_queue (kind, status)
+            VALUES ($1, 'running')
+            ON CONFLICT (kind) WHERE status IN ('new', 'running')
+            DO UPDATE SET status = 'running'
+            RETURNING
+                id,
+                kind AS "kind: JobKind",
+                status AS "status: _",
+                started,
+                last_object_id,
+                heartbeat
+            "#,
+            kind as JobKind,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    pub async fn heartbeat_job(
+        &self,
+        id: &Uuid,
+        last_object_id: &Uuid,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE job_queue SET heartbeat = now(), last_object_id = $2 \
+             WHERE id = $1",
+            id,
+            last_object_id,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn complete_job(&self, id: &Uuid) -> Result<()> {
+        sqlx::query!("DELETE FROM job_queue WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Requeues any `running` job whose heartbeat is older than `timeout`
+    /// as `new`. `claim_job` resumes such a row from `last_object_id`
+    /// whether its status is `new` or `running`.
+    pub async fn reap_stale_jobs(&self, timeout: Duration) -> Result<()> {
+        let timeout_secs = timeout.as_secs() as f64;
+
+        sqlx::query!(
+            "UPDATE job_queue SET status = 'new' \
+             WHERE status = 'running' \
+             AND heartbeat < now() - make_interval(secs => $1)",
+            timeout_secs,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_object_count(
+        &self,
+        since: Option<DateTime<Local>>,
+        start: DateTime<Local>,
+    ) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            "SELECT count(*) FROM objects \
+             WHERE created_at <= $1 \
+             AND ($2::timestamptz IS NULL OR updated_at > $2)",
+            start,
+            since,
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .unwrap_or(0);
+
+        Ok(count)
+    }
+
+    /// Streams every object created at or before `start` and, on an
+    /// incremental run, updated after `since`; ordered by id so a resumed
+    /// job can pick up after `resume_after`.
+    pub fn stream_objects_from(
+        &self,
+        since: Option<DateTime<Local>>,
+        start: DateTime<Local>,
+        resume_after: Option<Uuid>,
+    ) -> BoxStream<'_, Result<Object>> {
+        sqlx::query_as!(
+            Object,
+            r#"
+            SELECT object_id, hash, size
+            FROM objects
+            WHERE created_at <= $1
+            AND ($2::timestamptz IS NULL OR updated_at > $2)
+            AND ($3::uuid IS NULL OR object_id > $3)
+            ORDER BY object_id
+            "#,
+            start,
+            since,
+            resume_after,
+        )
+        .fetch(&self.pool)
+        .map(|row| row.map_err(Into::into))
+        .boxed()
+    }
+
+    pub async fn update_object_errors(
+        &self,
+        errors: &[ObjectError],
+    ) -> Result<()> {
+        for error in errors {
+            sqlx::query!(
+                "UPDATE objects SET error = $2 WHERE object_id = $1",
+                error.object_id,
+                error.message,
+            )
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+}
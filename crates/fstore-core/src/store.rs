@@ -1,3 +1,6 @@
+mod backend;
+mod job_queue;
+
 use crate::{
     db::{self, Database},
     error::{Error, OptionNotFound, Result},
@@ -7,17 +10,28 @@ use crate::{
     DbConnection, DbSupport,
 };
 
+pub use backend::{ArchiveConfig, CopyError, S3Config, Store};
+pub use job_queue::JobQueueOptions;
+
+// Visible to `db`, which persists and streams back `job_queue` rows.
+pub(crate) use job_queue::{Job, JobKind, JobStatus};
+
+use backend::{ArchiveManifest, Backend, ManifestEntry};
+use job_queue::JobHandle;
+
 use chrono::{DateTime, Local};
 use fstore::{Bucket, Object, ObjectError, RemoveResult, StoreTotals};
 use futures::stream::StreamExt;
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 use pgtools::{PgDump, PgRestore, Psql};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::{HashSet, VecDeque},
     future::Future,
     path::{Path, PathBuf},
     result,
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::{
     fs::File,
@@ -69,7 +83,80 @@ pub struct StoreOptions<'a> {
     pub version: Version,
     pub database: &'a DatabaseConfig,
     pub home: &'a Path,
-    pub archive: &'a Option<PathBuf>,
+    pub archive: &'a Option<ArchiveConfig>,
+    pub job_queue: JobQueueOptions,
+    pub stream_policy: StreamPolicy,
+}
+
+/// Controls how [`ObjectStore`]'s object-stream operations
+/// (`archive`/`check`/`repair`/`migrate_store`) tolerate faults.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamPolicy {
+    /// Record a missing object's blob as a non-fatal, skipped error
+    /// instead of letting it count as a hard failure.
+    pub skip_missing: bool,
+
+    /// How many times to re-establish the database object stream after a
+    /// transient fetch error before aborting the run.
+    pub max_stream_retries: u32,
+
+    /// How long to wait before re-establishing the stream after a
+    /// transient error.
+    pub retry_backoff: Duration,
+}
+
+impl Default for StreamPolicy {
+    fn default() -> Self {
+        Self {
+            skip_missing: false,
+            max_stream_retries: 3,
+            retry_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+fn is_missing_object_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("not found")
+        || message.contains("no such file")
+        || message.contains("missing")
+}
+
+/// Tracks objects dequeued from the stream to derive a genuine low-water
+/// mark for the job heartbeat: the newest id before which every dequeued
+/// object is guaranteed to have finished, even when later-dequeued objects
+/// race ahead of earlier ones and finish first.
+#[derive(Default)]
+struct CompletionTracker {
+    pending: VecDeque<Uuid>,
+    done: HashSet<Uuid>,
+    mark: Option<Uuid>,
+}
+
+impl CompletionTracker {
+    fn resuming_from(mark: Option<Uuid>) -> Self {
+        Self {
+            mark,
+            ..Self::default()
+        }
+    }
+
+    fn dequeue(&mut self, id: Uuid) {
+        self.pending.push_back(id);
+    }
+
+    fn complete(&mut self, id: Uuid) {
+        self.done.insert(id);
+
+        while let Some(&front) = self.pending.front() {
+            if !self.done.remove(&front) {
+                break;
+            }
+
+            self.pending.pop_front();
+            self.mark = Some(front);
+        }
+    }
 }
 
 trait ObjectStreamAction: Clone + Send + Sync + 'static {
@@ -77,7 +164,7 @@ trait ObjectStreamAction: Clone + Send + Sync + 'static {
         &self,
         store: &ObjectStore,
         object: &db::Object,
-    ) -> impl Future<Output = result::Result<(), String>> + Send;
+    ) -> impl Future<Output = result::Result<(), CopyError>> + Send;
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -88,24 +175,27 @@ impl ObjectStreamAction for CheckAction {
         &self,
         store: &ObjectStore,
         object: &db::Object,
-    ) -> result::Result<(), String> {
+    ) -> result::Result<(), CopyError> {
         store
             .filesystem
             .check(&object.object_id, &object.hash)
             .await
+            .map_err(|message| CopyError {
+                missing: is_missing_object_error(&message),
+                message,
+            })
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 struct SyncAction {
-    archive: Arc<PathBuf>,
+    archive: Arc<Backend>,
+    manifest: Arc<Mutex<ArchiveManifest>>,
 }
 
 impl SyncAction {
-    fn new(path: &Path) -> Self {
-        Self {
-            archive: Arc::new(path.to_owned()),
-        }
+    fn new(archive: Arc<Backend>, manifest: Arc<Mutex<ArchiveManifest>>) -> Self {
+        Self { archive, manifest }
     }
 }
 
@@ -114,11 +204,120 @@ impl ObjectStreamAction for SyncAction {
         &self,
         store: &ObjectStore,
         object: &db::Object,
-    ) -> result::Result<(), String> {
+    ) -> result::Result<(), CopyError> {
+        let unchanged = {
+            let manifest = self.manifest.lock().unwrap();
+            manifest
+                .entries
+                .get(&object.object_id)
+                .is_some_and(|entry| entry.hash == object.hash)
+        };
+
+        if unchanged {
+            return Ok(());
+        }
+
+        Store::copy(
+            &store.filesystem,
+            &object.object_id,
+            self.archive.as_ref(),
+            &object.hash,
+        )
+        .await?;
+
+        self.manifest.lock().unwrap().entries.insert(
+            object.object_id,
+            ManifestEntry {
+                hash: object.hash.clone(),
+                size: object.size,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct RepairAction {
+    archive: Arc<Backend>,
+}
+
+impl RepairAction {
+    fn new(archive: Arc<Backend>) -> Self {
+        Self { archive }
+    }
+}
+
+impl ObjectStreamAction for RepairAction {
+    async fn run(
+        &self,
+        store: &ObjectStore,
+        object: &db::Object,
+    ) -> result::Result<(), CopyError> {
+        if store
+            .filesystem
+            .check(&object.object_id, &object.hash)
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+
+        Store::copy(
+            self.archive.as_ref(),
+            &object.object_id,
+            &store.filesystem,
+            &object.hash,
+        )
+        .await?;
+
+        store
+            .filesystem
+            .check(&object.object_id, &object.hash)
+            .await
+            .map_err(|message| CopyError {
+                missing: is_missing_object_error(&message),
+                message,
+            })
+    }
+}
+
+#[derive(Clone)]
+struct MigrateAction {
+    destination: Arc<Backend>,
+    skip_missing_files: bool,
+}
+
+impl ObjectStreamAction for MigrateAction {
+    async fn run(
+        &self,
+        store: &ObjectStore,
+        object: &db::Object,
+    ) -> result::Result<(), CopyError> {
+        if let Err(err) = Store::copy(
+            &store.filesystem,
+            &object.object_id,
+            self.destination.as_ref(),
+            &object.hash,
+        )
+        .await
+        {
+            return if self.skip_missing_files && err.missing {
+                info!(
+                    "skipping object {} during migration: {err}",
+                    object.object_id
+                );
+                Ok(())
+            } else {
+                Err(err)
+            };
+        }
+
         store
             .filesystem
-            .copy(&object.object_id, self.archive.as_path(), &object.hash)
+            .remove_objects(std::iter::once(&object.object_id))
             .await
+            .map_err(|err| CopyError::other(err.to_string()))
     }
 }
 
@@ -126,6 +325,8 @@ impl ObjectStreamAction for SyncAction {
 pub struct Tasks {
     pub archive: Task,
     pub check: Task,
+    pub repair: Task,
+    pub migrate: Task,
 }
 
 pub struct ObjectStore {
@@ -135,7 +336,9 @@ pub struct ObjectStore {
     database: Database,
     db_support: DbSupport,
     filesystem: Filesystem,
-    archive: Option<PathBuf>,
+    archive: Option<Arc<Backend>>,
+    job_queue: JobQueueOptions,
+    stream_policy: StreamPolicy,
 }
 
 impl ObjectStore {
@@ -154,6 +357,11 @@ impl ObjectStore {
             },
         )?;
 
+        let archive = match options.archive {
+            Some(config) => Some(Arc::new(Backend::new(config)?)),
+            None => None,
+        };
+
         Ok(Self {
             about: About {
                 version: options.version,
@@ -161,11 +369,33 @@ impl ObjectStore {
             database,
             db_support,
             filesystem: Filesystem::new(options.home),
-            archive: options.archive.clone(),
+            archive,
+            job_queue: options.job_queue,
+            stream_policy: options.stream_policy,
             tasks: Default::default(),
         })
     }
 
+    pub async fn reap_stale_jobs(&self) -> Result<()> {
+        self.database
+            .reap_stale_jobs(self.job_queue.reap_timeout)
+            .await
+    }
+
+    pub fn spawn_reaper(self: Arc<Self>) -> JoinHandle<()> {
+        let mut interval = tokio::time::interval(self.job_queue.reap_timeout);
+
+        task::spawn(async move {
+            loop {
+                interval.tick().await;
+
+                if let Err(err) = self.reap_stale_jobs().await {
+                    error!("failed to reap stale jobs: {err}");
+                }
+            }
+        })
+    }
+
     pub async fn prepare(&self) -> result::Result<(), String> {
         self.db_support.check_schema_version().await?;
 
@@ -174,35 +404,64 @@ impl ObjectStore {
 
     pub async fn archive(
         self: Arc<Self>,
+        full: bool,
     ) -> Result<(Progress, JoinHandle<Result<()>>)> {
-        let archive = self.archive.as_deref().ok_or_else(|| {
-            Error::Internal("archive location not specified".into())
-        })?;
-
-        let started = Local::now();
-        let total = self.get_object_count(started).await?;
-        let guard =
-            ProgressGuard::new(started, total, self.tasks.archive.clone())?;
+        let archive = self
+            .archive
+            .clone()
+            .ok_or_else(|| Error::Internal("archive location not specified".into()))?;
+
+        let manifest = if full {
+            ArchiveManifest::default()
+        } else {
+            archive.load_manifest().await?
+        };
+        let since = manifest.last_archived_at;
+
+        let job = self.database.claim_job(JobKind::Archive).await?;
+        let total = self.get_object_count(since, job.started).await?;
+        let guard = ProgressGuard::resume(
+            job.started,
+            total,
+            self.tasks.archive.clone(),
+            job.last_object_id,
+        )?;
 
-        tokio::fs::create_dir_all(archive).await.map_err(|err| {
-            Error::Internal(format!(
-                "Failed to create archive directory '{}': {err}",
-                archive.display()
-            ))
-        })?;
+        archive.prepare().await.map_err(Error::Internal)?;
 
-        let dump = archive.join(DATABASE_DUMP_FILENAME);
-        self.db_support.dump(&dump).await.map_err(Error::Internal)?;
+        if let Some(dir) = archive.local_path() {
+            let dump = dir.join(DATABASE_DUMP_FILENAME);
+            self.db_support.dump(&dump).await.map_err(Error::Internal)?;
+        }
 
-        self.filesystem.remove_extraneous(archive).await?;
+        if full {
+            archive.remove_extraneous().await?;
+        }
 
         let progress = guard.clone();
-        let action = SyncAction::new(archive);
+        let started = job.started;
+        let manifest = Arc::new(Mutex::new(manifest));
+        let action = SyncAction::new(archive.clone(), manifest.clone());
+        let job = JobHandle::new(job.id, self.database.clone());
+
+        let handle = task::spawn(async move {
+            let result = self
+                .for_each_object(guard, action, job.clone(), since)
+                .await;
+
+            if result.is_ok() {
+                Self::finish_job(&job, "archive").await;
+
+                let mut manifest = manifest.lock().unwrap().clone();
+                manifest.last_archived_at = Some(started);
 
-        let handle =
-            task::spawn(
-                async move { self.for_each_object(guard, action).await },
-            );
+                if let Err(err) = archive.save_manifest(&manifest).await {
+                    error!("failed to save archive manifest: {err}");
+                }
+            }
+
+            result
+        });
 
         Ok((progress, handle))
     }
@@ -210,15 +469,105 @@ impl ObjectStore {
     pub async fn check(
         self: Arc<Self>,
     ) -> Result<(Progress, JoinHandle<Result<()>>)> {
-        let started = Local::now();
-        let total = self.get_object_count(started).await?;
-        let guard =
-            ProgressGuard::new(started, total, self.tasks.check.clone())?;
+        let job = self.database.claim_job(JobKind::Check).await?;
+        let total = self.get_object_count(None, job.started).await?;
+        let guard = ProgressGuard::resume(
+            job.started,
+            total,
+            self.tasks.check.clone(),
+            job.last_object_id,
+        )?;
 
         let progress = guard.clone();
+        let job = JobHandle::new(job.id, self.database.clone());
 
         let handle = task::spawn(async move {
-            self.for_each_object(guard, CheckAction).await
+            let result = self
+                .for_each_object(guard, CheckAction, job.clone(), None)
+                .await;
+
+            if result.is_ok() {
+                Self::finish_job(&job, "check").await;
+            }
+
+            result
+        });
+
+        Ok((progress, handle))
+    }
+
+    pub async fn repair(
+        self: Arc<Self>,
+    ) -> Result<(Progress, JoinHandle<Result<()>>)> {
+        let archive = self
+            .archive
+            .clone()
+            .ok_or_else(|| Error::Internal("archive location not specified".into()))?;
+
+        let job = self.database.claim_job(JobKind::Repair).await?;
+        let total = self.get_object_count(None, job.started).await?;
+        let guard = ProgressGuard::resume(
+            job.started,
+            total,
+            self.tasks.repair.clone(),
+            job.last_object_id,
+        )?;
+
+        let progress = guard.clone();
+        let action = RepairAction::new(archive);
+        let job = JobHandle::new(job.id, self.database.clone());
+
+        let handle = task::spawn(async move {
+            let result = self
+                .for_each_object(guard, action, job.clone(), None)
+                .await;
+
+            if result.is_ok() {
+                Self::finish_job(&job, "repair").await;
+            }
+
+            result
+        });
+
+        Ok((progress, handle))
+    }
+
+    pub async fn migrate_store(
+        self: Arc<Self>,
+        destination: ArchiveConfig,
+        skip_missing_files: bool,
+    ) -> Result<(Progress, JoinHandle<Result<()>>)> {
+        let destination =
+            Arc::new(Backend::new(&destination).map_err(Error::Internal)?);
+
+        destination.prepare().await.map_err(Error::Internal)?;
+
+        let job = self.database.claim_job(JobKind::Migrate).await?;
+        let total = self.get_object_count(None, job.started).await?;
+        let guard = ProgressGuard::resume(
+            job.started,
+            total,
+            self.tasks.migrate.clone(),
+            job.last_object_id,
+        )?;
+
+        let progress = guard.clone();
+        let action = MigrateAction {
+            destination,
+            skip_missing_files,
+        };
+        let job = JobHandle::new(job.id, self.database.clone());
+
+        let handle = task::spawn(async move {
+            let result = self
+                .for_each_object(guard, action, job.clone(), None)
+                .await;
+
+            if result.is_ok() {
+                Self::finish_job(&job, "migration").await;
+            }
+
+            result
         });
 
         Ok((progress, handle))
@@ -424,10 +773,20 @@ impl ObjectStore {
         self.database.close().await
     }
 
-    async fn get_object_count(&self, start: DateTime<Local>) -> Result<u64> {
+    async fn finish_job(job: &JobHandle, kind: &str) {
+        if let Err(err) = job.complete().await {
+            error!("failed to complete {kind} job: {err}");
+        }
+    }
+
+    async fn get_object_count(
+        &self,
+        since: Option<DateTime<Local>>,
+        start: DateTime<Local>,
+    ) -> Result<u64> {
         let total = self
             .database
-            .get_object_count(start)
+            .get_object_count(since, start)
             .await
             .map_err(|err| {
                 Error::Internal(format!("failed to fetch object count: {err}"))
@@ -442,35 +801,93 @@ impl ObjectStore {
         self: Arc<Self>,
         progress: ProgressGuard,
         action: impl ObjectStreamAction,
+        job: JobHandle,
+        since: Option<DateTime<Local>>,
     ) -> Result<()> {
+        let policy = self.stream_policy;
         let tracker = TaskTracker::new();
         let semaphore = Arc::new(Semaphore::new(num_cpus::get()));
         let mut error: Option<Error> = None;
-        let mut stream = self.database.stream_objects(progress.started());
-
-        'stream: while let Some(object) = stream.next().await {
-            let object = match object {
-                Ok(object) => object,
-                Err(err) => {
-                    error = Some(Error::Internal(format!(
-                        "failed to fetch object from database: {err}"
-                    )));
-                    break 'stream;
+        let mut last_heartbeat = Instant::now();
+        let mut last_seen = progress.resume_point();
+        let completed = Arc::new(Mutex::new(CompletionTracker::resuming_from(
+            progress.resume_point(),
+        )));
+        let mut stream_retries = 0;
+        let mut stream =
+            self.database
+                .stream_objects_from(since, progress.started(), last_seen);
+
+        'stream: loop {
+            let object = match stream.next().await {
+                None => break 'stream,
+                Some(Ok(object)) => {
+                    stream_retries = 0;
+                    object
+                }
+                Some(Err(err)) => {
+                    stream_retries += 1;
+
+                    if stream_retries > policy.max_stream_retries {
+                        error = Some(Error::Internal(format!(
+                            "failed to fetch object from database after \
+                             {stream_retries} attempts: {err}"
+                        )));
+                        break 'stream;
+                    }
+
+                    warn!(
+                        "transient error reading object stream (attempt \
+                         {stream_retries}/{}): {err}",
+                        policy.max_stream_retries
+                    );
+
+                    tokio::time::sleep(policy.retry_backoff).await;
+                    stream = self.database.stream_objects_from(
+                        since,
+                        progress.started(),
+                        last_seen,
+                    );
+
+                    continue 'stream;
                 }
             };
 
+            last_seen = Some(object.object_id);
+            completed.lock().unwrap().dequeue(object.object_id);
+
+            if last_heartbeat.elapsed() >= self.job_queue.heartbeat_interval {
+                let completed_mark = completed.lock().unwrap().mark;
+
+                if let Some(completed_mark) = completed_mark {
+                    if let Err(err) = job.beat(completed_mark).await {
+                        error!("failed to update job heartbeat: {err}");
+                    }
+                }
+
+                last_heartbeat = Instant::now();
+            }
+
             let permit = semaphore.clone().acquire_owned().await.unwrap();
             let store = self.clone();
             let progress = progress.clone();
             let action = action.clone();
+            let completed = completed.clone();
 
             tracker.spawn(async move {
                 let messages = match action.run(&store, &object).await {
                     Ok(()) => progress.clear_error(object.object_id),
-                    Err(message) => progress.error(object.object_id, message),
+                    Err(err) => {
+                        if policy.skip_missing && err.missing {
+                            progress.skip(object.object_id, err.message)
+                        } else {
+                            progress.error(object.object_id, err.message)
+                        }
+                    }
                 };
 
                 progress.increment();
+                completed.lock().unwrap().complete(object.object_id);
                 drop(permit);
 
                 if !messages.is_empty() {
@@ -497,6 +914,13 @@ impl ObjectStore {
             }
         }
 
+        info!(
+            "stream finished: {} ok, {} skipped (missing), {} errored",
+            progress.ok_count(),
+            progress.skipped_count(),
+            progress.error_count(),
+        );
+
         match error {
             Some(err) => Err(err),
             None => Ok(()),
@@ -0,0 +1,533 @@
+use super::is_missing_object_error;
+
+use crate::{
+    error::{Error, Result},
+    fs::{Filesystem, Part},
+};
+
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use chrono::{DateTime, Local};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, path::PathBuf, result};
+use tokio::fs::File;
+use uuid::Uuid;
+
+const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// User-metadata key [`S3Store`] stamps on every upload with the object's
+/// content hash, since an S3 `ETag` is only a plain MD5 digest for
+/// non-multipart, unencrypted uploads and can't be relied on as a stand-in
+/// for whatever hash algorithm `object.hash` actually uses.
+const S3_HASH_METADATA_KEY: &str = "content-hash";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArchiveConfig {
+    Filesystem { path: PathBuf },
+    S3(S3Config),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+
+    #[serde(default)]
+    pub endpoint: Option<String>,
+
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
+/// The outcome of a failed [`Store::put`]/[`Store::check`]/[`Store::copy`]:
+/// whether `id`'s blob is genuinely missing from the source (safe to skip
+/// under `--skip-missing-files`) or some other, harder failure, classified
+/// from whatever typed signal the backend that raised it has on hand.
+#[derive(Debug)]
+pub struct CopyError {
+    pub missing: bool,
+    pub message: String,
+}
+
+impl CopyError {
+    pub(crate) fn other(message: impl Into<String>) -> Self {
+        Self {
+            missing: false,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn missing(message: impl Into<String>) -> Self {
+        Self {
+            missing: true,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for CopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<Error> for CopyError {
+    fn from(err: Error) -> Self {
+        let missing = matches!(err, Error::NotFound(_));
+
+        Self {
+            missing,
+            message: err.to_string(),
+        }
+    }
+}
+
+/// A storage backend capable of holding object blobs.
+///
+/// `Filesystem` is the original, local-disk implementation. Anything else
+/// implementing this trait (e.g. [`S3Store`]) can stand in for it as an
+/// `archive` target.
+pub trait Store: Send + Sync {
+    fn object<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, Result<File>>;
+
+    fn put<'a>(
+        &'a self,
+        id: &'a Uuid,
+        source: &'a mut File,
+        hash: &'a str,
+    ) -> BoxFuture<'a, result::Result<(), CopyError>>;
+
+    fn check<'a>(
+        &'a self,
+        id: &'a Uuid,
+        hash: &'a str,
+    ) -> BoxFuture<'a, result::Result<(), CopyError>>;
+
+    fn remove_objects<'a>(
+        &'a self,
+        ids: &'a [Uuid],
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Copies the object `id` from `self` into `dst`, verifying `hash` along
+    /// the way. The default implementation streams through [`Store::object`]
+    /// and [`Store::put`], which works for any pair of backends.
+    fn copy<'a>(
+        &'a self,
+        id: &'a Uuid,
+        dst: &'a dyn Store,
+        hash: &'a str,
+    ) -> BoxFuture<'a, result::Result<(), CopyError>> {
+        Box::pin(async move {
+            let mut source = self.object(id).await?;
+
+            dst.put(id, &mut source, hash).await
+        })
+    }
+}
+
+impl Store for Filesystem {
+    fn object<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, Result<File>> {
+        Box::pin(self.object(id))
+    }
+
+    fn put<'a>(
+        &'a self,
+        id: &'a Uuid,
+        source: &'a mut File,
+        hash: &'a str,
+    ) -> BoxFuture<'a, result::Result<(), CopyError>> {
+        Box::pin(async move {
+            let part: Part = self.part(id).await.map_err(CopyError::from)?;
+
+            let mut staged = File::create(&part.path)
+                .await
+                .map_err(|err| CopyError::other(err.to_string()))?;
+
+            tokio::io::copy(source, &mut staged)
+                .await
+                .map_err(|err| CopyError::other(err.to_string()))?;
+
+            let metadata = self.commit(id).await.map_err(CopyError::from)?;
+
+            if metadata.hash != hash {
+                return Err(CopyError::other(format!(
+                    "hash mismatch restoring object '{id}': expected '{hash}', got '{}'",
+                    metadata.hash
+                )));
+            }
+
+            Ok(())
+        })
+    }
+
+    fn check<'a>(
+        &'a self,
+        id: &'a Uuid,
+        hash: &'a str,
+    ) -> BoxFuture<'a, result::Result<(), CopyError>> {
+        Box::pin(async move {
+            // `Filesystem::check` only reports failures as a message, so
+            // this is the best signal available for classifying a missing
+            // local blob.
+            self.check(id, hash).await.map_err(|message| CopyError {
+                missing: is_missing_object_error(&message),
+                message,
+            })
+        })
+    }
+
+    fn remove_objects<'a>(
+        &'a self,
+        ids: &'a [Uuid],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.remove_objects(ids.iter()))
+    }
+}
+
+/// An S3-compatible object storage backend, typically used as an off-host
+/// `archive` target.
+pub struct S3Store {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: Option<String>,
+}
+
+impl S3Store {
+    pub fn new(config: &S3Config) -> result::Result<Self, String> {
+        let mut builder = aws_sdk_s3::config::Builder::new()
+            .region(aws_sdk_s3::config::Region::new(config.region.clone()))
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest());
+
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        Ok(Self {
+            client: aws_sdk_s3::Client::from_conf(builder.build()),
+            bucket: config.bucket.clone(),
+            prefix: config.prefix.clone(),
+        })
+    }
+
+    fn key(&self, id: &Uuid) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{prefix}/{id}"),
+            None => id.to_string(),
+        }
+    }
+}
+
+impl Store for S3Store {
+    fn object<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, Result<File>> {
+        Box::pin(async move {
+            let object = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(self.key(id))
+                .send()
+                .await
+                .map_err(|err| {
+                    if err.as_service_error().is_some_and(|err| err.is_no_such_key())
+                    {
+                        Error::NotFound(format!(
+                            "object '{id}' not found in S3"
+                        ))
+                    } else {
+                        Error::Internal(format!(
+                            "failed to fetch object '{id}' from S3: {err}"
+                        ))
+                    }
+                })?;
+
+            let mut file = File::from_std(tempfile::tempfile().map_err(
+                |err| Error::Internal(format!("failed to stage object '{id}': {err}")),
+            )?);
+
+            let mut body = object.body.into_async_read();
+
+            tokio::io::copy(&mut body, &mut file).await.map_err(|err| {
+                Error::Internal(format!(
+                    "failed to download object '{id}' from S3: {err}"
+                ))
+            })?;
+
+            use tokio::io::AsyncSeekExt;
+            file.seek(std::io::SeekFrom::Start(0)).await.map_err(|err| {
+                Error::Internal(format!("failed to rewind object '{id}': {err}"))
+            })?;
+
+            Ok(file)
+        })
+    }
+
+    fn put<'a>(
+        &'a self,
+        id: &'a Uuid,
+        source: &'a mut File,
+        hash: &'a str,
+    ) -> BoxFuture<'a, result::Result<(), CopyError>> {
+        Box::pin(async move {
+            use tokio::io::AsyncSeekExt;
+            source
+                .seek(std::io::SeekFrom::Start(0))
+                .await
+                .map_err(|err| CopyError::other(err.to_string()))?;
+
+            let len = source
+                .metadata()
+                .await
+                .map_err(|err| CopyError::other(err.to_string()))?
+                .len();
+
+            let file = source
+                .try_clone()
+                .await
+                .map_err(|err| CopyError::other(err.to_string()))?;
+
+            let body = ByteStream::read_from()
+                .file(file)
+                .length(Length::Exact(len))
+                .build()
+                .await
+                .map_err(|err| CopyError::other(err.to_string()))?;
+
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(self.key(id))
+                .body(body)
+                .metadata(S3_HASH_METADATA_KEY, hash)
+                .send()
+                .await
+                .map_err(|err| {
+                    CopyError::other(format!(
+                        "failed to upload object '{id}' to S3: {err}"
+                    ))
+                })?;
+
+            self.check(id, hash).await
+        })
+    }
+
+    fn check<'a>(
+        &'a self,
+        id: &'a Uuid,
+        hash: &'a str,
+    ) -> BoxFuture<'a, result::Result<(), CopyError>> {
+        Box::pin(async move {
+            let head = self
+                .client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(self.key(id))
+                .send()
+                .await
+                .map_err(|err| {
+                    if err.as_service_error().is_some_and(|err| err.is_not_found())
+                    {
+                        CopyError::missing(format!(
+                            "object '{id}' missing from S3 archive"
+                        ))
+                    } else {
+                        CopyError::other(format!(
+                            "failed to check object '{id}' in S3 archive: {err}"
+                        ))
+                    }
+                })?;
+
+            match head
+                .metadata()
+                .and_then(|metadata| metadata.get(S3_HASH_METADATA_KEY))
+            {
+                Some(stored) if stored == hash => Ok(()),
+                _ => Err(CopyError::other(format!(
+                    "hash mismatch for archived object '{id}'"
+                ))),
+            }
+        })
+    }
+
+    fn remove_objects<'a>(
+        &'a self,
+        ids: &'a [Uuid],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for id in ids {
+                self.client
+                    .delete_object()
+                    .bucket(&self.bucket)
+                    .key(self.key(id))
+                    .send()
+                    .await
+                    .map_err(|err| {
+                        Error::Internal(format!(
+                            "failed to remove object '{id}' from S3: {err}"
+                        ))
+                    })?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// The archive's record of which object blobs it already holds, persisted
+/// alongside the archive so that a later `archive` run can skip objects
+/// whose blob hasn't changed since it was last copied.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct ArchiveManifest {
+    pub last_archived_at: Option<DateTime<Local>>,
+    pub entries: HashMap<Uuid, ManifestEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ManifestEntry {
+    pub hash: String,
+    pub size: i64,
+}
+
+/// The concrete archive backend selected by [`ArchiveConfig`].
+pub enum Backend {
+    Filesystem(Filesystem),
+    S3(S3Store),
+}
+
+impl Backend {
+    pub fn new(config: &ArchiveConfig) -> result::Result<Self, String> {
+        Ok(match config {
+            ArchiveConfig::Filesystem { path } => {
+                Self::Filesystem(Filesystem::new(path))
+            }
+            ArchiveConfig::S3(config) => Self::S3(S3Store::new(config)?),
+        })
+    }
+
+    pub fn local_path(&self) -> Option<&std::path::Path> {
+        match self {
+            Self::Filesystem(fs) => Some(fs.home()),
+            Self::S3(_) => None,
+        }
+    }
+
+    pub async fn prepare(&self) -> result::Result<(), String> {
+        match self {
+            Self::Filesystem(fs) => {
+                tokio::fs::create_dir_all(fs.home())
+                    .await
+                    .map_err(|err| {
+                        format!(
+                            "failed to create archive directory '{}': {err}",
+                            fs.home().display()
+                        )
+                    })
+            }
+            Self::S3(_) => Ok(()),
+        }
+    }
+
+    pub async fn remove_extraneous(&self) -> Result<()> {
+        match self {
+            Self::Filesystem(fs) => fs.remove_extraneous(fs.home()).await,
+            Self::S3(_) => Ok(()),
+        }
+    }
+
+    /// Loads the archive manifest left by the previous `archive` run, or an
+    /// empty manifest if none exists yet (or this backend doesn't persist
+    /// one, as with [`Backend::S3`]).
+    pub(crate) async fn load_manifest(&self) -> Result<ArchiveManifest> {
+        let Some(dir) = self.local_path() else {
+            return Ok(ArchiveManifest::default());
+        };
+
+        let path = dir.join(MANIFEST_FILENAME);
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(ArchiveManifest::default())
+            }
+            Err(err) => {
+                return Err(Error::Internal(format!(
+                    "failed to read archive manifest '{}': {err}",
+                    path.display()
+                )))
+            }
+        };
+
+        serde_json::from_slice(&bytes).map_err(|err| {
+            Error::Internal(format!(
+                "failed to parse archive manifest '{}': {err}",
+                path.display()
+            ))
+        })
+    }
+
+    /// Persists the archive manifest for the next `archive` run to resume
+    /// from. A no-op for backends without a local directory to write to.
+    pub(crate) async fn save_manifest(
+        &self,
+        manifest: &ArchiveManifest,
+    ) -> Result<()> {
+        let Some(dir) = self.local_path() else {
+            return Ok(());
+        };
+
+        let path = dir.join(MANIFEST_FILENAME);
+        let bytes = serde_json::to_vec_pretty(manifest).map_err(|err| {
+            Error::Internal(format!(
+                "failed to serialize archive manifest: {err}"
+            ))
+        })?;
+
+        tokio::fs::write(&path, bytes).await.map_err(|err| {
+            Error::Internal(format!(
+                "failed to write archive manifest '{}': {err}",
+                path.display()
+            ))
+        })
+    }
+}
+
+impl Store for Backend {
+    fn object<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, Result<File>> {
+        match self {
+            Self::Filesystem(fs) => Store::object(fs, id),
+            Self::S3(s3) => s3.object(id),
+        }
+    }
+
+    fn put<'a>(
+        &'a self,
+        id: &'a Uuid,
+        source: &'a mut File,
+        hash: &'a str,
+    ) -> BoxFuture<'a, result::Result<(), CopyError>> {
+        match self {
+            Self::Filesystem(fs) => fs.put(id, source, hash),
+            Self::S3(s3) => s3.put(id, source, hash),
+        }
+    }
+
+    fn check<'a>(
+        &'a self,
+        id: &'a Uuid,
+        hash: &'a str,
+    ) -> BoxFuture<'a, result::Result<(), CopyError>> {
+        match self {
+            Self::Filesystem(fs) => Store::check(fs, id, hash),
+            Self::S3(s3) => s3.check(id, hash),
+        }
+    }
+
+    fn remove_objects<'a>(
+        &'a self,
+        ids: &'a [Uuid],
+    ) -> BoxFuture<'a, Result<()>> {
+        match self {
+            Self::Filesystem(fs) => Store::remove_objects(fs, ids),
+            Self::S3(s3) => s3.remove_objects(ids),
+        }
+    }
+}
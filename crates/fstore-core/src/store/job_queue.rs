@@ -0,0 +1,86 @@
+use crate::{db::Database, error::Result};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The operation a `job_queue` row is tracking.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, sqlx::Type,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "job_kind", rename_all = "snake_case")]
+pub enum JobKind {
+    Archive,
+    Check,
+    Repair,
+    Migrate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "job_status", rename_all = "snake_case")]
+pub enum JobStatus {
+    New,
+    Running,
+}
+
+/// A row of the `job_queue` table.
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    pub started: DateTime<Local>,
+    pub last_object_id: Option<Uuid>,
+    pub heartbeat: DateTime<Local>,
+}
+
+/// A handle to a claimed `job_queue` row, held by the worker driving it.
+///
+/// Dropping the handle without calling [`JobHandle::complete`] leaves the
+/// row `running`; the reaper requeues it once its heartbeat goes stale,
+/// letting a future worker resume from `last_object_id`.
+#[derive(Clone)]
+pub struct JobHandle {
+    id: Uuid,
+    database: Database,
+}
+
+impl JobHandle {
+    pub(crate) fn new(id: Uuid, database: Database) -> Self {
+        Self { id, database }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub async fn beat(&self, last_object_id: Uuid) -> Result<()> {
+        self.database.heartbeat_job(&self.id, &last_object_id).await
+    }
+
+    pub async fn complete(&self) -> Result<()> {
+        self.database.complete_job(&self.id).await
+    }
+}
+
+/// Tuning for the job queue worker/reaper pair.
+#[derive(Clone, Copy, Debug)]
+pub struct JobQueueOptions {
+    /// How often a running job updates its `heartbeat` column.
+    pub heartbeat_interval: Duration,
+
+    /// How long a `running` job may go without a heartbeat before the
+    /// reaper requeues it as `new`.
+    pub reap_timeout: Duration,
+}
+
+impl Default for JobQueueOptions {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(30),
+            reap_timeout: Duration::from_secs(300),
+        }
+    }
+}
@@ -3,7 +3,8 @@ use fstored::{
     store,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use fstore_core::ArchiveConfig;
 use std::{env, path::PathBuf, process::ExitCode};
 
 const COMPILE_CONFIG: Option<&str> = option_env!("FSTORED_DEFAULT_CONFIG");
@@ -14,6 +15,46 @@ const DEFAULT_CONFIG: &str = "/etc/fstore/fstore.yml";
 pub struct Cli {
     #[arg(short, long, value_name = "FILE")]
     config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Relocate every object's backing blob to another storage backend.
+    MigrateStore {
+        /// Destination directory, or an `s3://bucket/prefix` URL.
+        destination: String,
+
+        /// Log and skip objects whose source blob is already missing.
+        /// Without this flag each such object is recorded as an object
+        /// error; the migration goes on either way.
+        #[arg(long)]
+        skip_missing_files: bool,
+    },
+}
+
+fn parse_destination(destination: &str) -> Result<ArchiveConfig, String> {
+    match destination.strip_prefix("s3://") {
+        Some(rest) => {
+            let (bucket, prefix) = match rest.split_once('/') {
+                Some((bucket, prefix)) => (bucket, Some(prefix.to_owned())),
+                None => (rest, None),
+            };
+
+            Ok(ArchiveConfig::S3(fstore_core::S3Config {
+                bucket: bucket.to_owned(),
+                region: env::var("AWS_REGION")
+                    .unwrap_or_else(|_| "us-east-1".into()),
+                endpoint: env::var("AWS_ENDPOINT_URL").ok(),
+                prefix,
+            }))
+        }
+        None => Ok(ArchiveConfig::Filesystem {
+            path: PathBuf::from(destination),
+        }),
+    }
 }
 
 fn main() -> ExitCode {
@@ -38,7 +79,7 @@ fn main() -> ExitCode {
         }
     };
 
-    if let Err(err) = run(&config) {
+    if let Err(err) = run(&config, cli.command) {
         eprintln!("{err}");
         return ExitCode::FAILURE;
     }
@@ -47,14 +88,34 @@ fn main() -> ExitCode {
 }
 
 #[tokio::main]
-async fn run(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+async fn run(
+    config: &Config,
+    command: Option<Command>,
+) -> Result<(), Box<dyn std::error::Error>> {
     let object_store = store::start(config).await?;
+    let _reaper = object_store.clone().spawn_reaper();
+
+    match command {
+        Some(Command::MigrateStore {
+            destination,
+            skip_missing_files,
+        }) => {
+            let destination = parse_destination(&destination)?;
+            let (progress, handle) = object_store
+                .migrate_store(destination, skip_missing_files)
+                .await?;
 
-    let totals = object_store.get_totals().await?;
-    println!(
-        "Buckets: {}\nObjects: {}\nSpace used: {}",
-        totals.buckets, totals.objects, totals.space_used
-    );
+            println!("Migrating {} object(s)...", progress.total());
+            handle.await??;
+        }
+        None => {
+            let totals = object_store.get_totals().await?;
+            println!(
+                "Buckets: {}\nObjects: {}\nSpace used: {}",
+                totals.buckets, totals.objects, totals.space_used
+            );
+        }
+    }
 
     Ok(())
 }
\ No newline at end of file